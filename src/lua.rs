@@ -1,4 +1,12 @@
-use std::ops::Deref;
+use std::{
+    cell::Cell,
+    future::Future,
+    ops::Deref,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use gc_arena::{DynamicRootSet, Rootable};
 use mlua::prelude::*;
@@ -14,8 +22,6 @@ type LuaArena = gc_arena::Arena<Rootable![DynamicRootSet<'_>]>;
 
 trait LuaExt {
     fn gc(&self) -> mlua::AppDataRef<LuaArena>;
-    fn protect_table(&self, table: &LuaTable) -> LuaResult<()>;
-    fn create_protected_table(&self) -> LuaResult<LuaTable>;
     fn create_overlay_table(&self, lower: &LuaTable) -> LuaResult<LuaTable>;
 }
 
@@ -25,44 +31,16 @@ impl LuaExt for Lua {
             .expect("lua object should contain a dynamic gc arena")
     }
 
-    fn protect_table(&self, table: &LuaTable) -> LuaResult<()> {
-        let metatable = self.create_table()?;
-
-        let cloned = table.clone();
-        metatable.raw_set(
-            "__index",
-            self.create_function(move |_, (_, key): (LuaValue, LuaValue)| cloned.raw_get::<LuaValue>(key))?,
-        )?;
-        metatable.raw_set(
-            "__newindex",
-            self.create_function(|_, _: ()| Err::<(), _>(LuaError::runtime("attempt to update a protected table")))?,
-        )?;
-        metatable.raw_set("__metatable", LuaValue::Boolean(true))?;
-
-        table.set_metatable(Some(metatable));
-
-        Ok(())
-    }
-
-    fn create_protected_table(&self) -> LuaResult<LuaTable> {
-        let table = self.create_table()?;
-        self.protect_table(&table)?;
-        Ok(table)
-    }
-
+    // Luau enforces read-only tables natively (`Table::set_readonly`), so unlike
+    // the old metatable-based scheme the `lower` table here is trusted to already
+    // be frozen. We only need `__index` to fall through to it; there is no need
+    // for a hand-rolled `__newindex` since writes into `upper` are meant to
+    // succeed (it's the script's own global table) and writes into `lower` are
+    // rejected by the VM itself regardless of raw/metatable access.
     fn create_overlay_table(&self, lower: &LuaTable) -> LuaResult<LuaTable> {
         let upper = self.create_table()?;
         let metatable = self.create_table()?;
         metatable.raw_set("__index", lower)?;
-
-        let upper_clone = upper.clone();
-        // NOTE: The table parameter is intentionally ignore to avoid providing
-        //       a "raw_set on anything primitive".
-        metatable.raw_set(
-            "__newindex",
-            self.create_function(move |_, (_t, k, v): (LuaTable, LuaValue, LuaValue)| upper_clone.raw_set(k, v))?,
-        )?;
-
         metatable.raw_set("__metatable", LuaValue::Boolean(true))?;
 
         upper.set_metatable(Some(metatable));
@@ -74,11 +52,183 @@ impl LuaExt for Lua {
 pub struct ModLuaRuntime {
     lua: Lua,
     lib_table: LuaTable,
+    bytecode_cache_dir: PathBuf,
 }
 
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A Rust-backed entry in `package.searchers`, following Lua's standard
+/// searcher protocol: given a module name, return either a loader function
+/// plus the path it was found at (for `package.loaded`/error messages), or a
+/// string explaining why this searcher didn't have the module so `require`
+/// can fold it into the usual `module 'x' not found` message.
+pub type PackageSearcher = Box<dyn Fn(&Lua, String) -> LuaResult<LuaMultiValue> + Send + Sync>;
+
+/// Built-in searcher that resolves `require "name"` against a configurable
+/// set of trusted library roots (e.g. a directory of community-shared helper
+/// scripts shipped alongside modpacks), compiling and caching the result the
+/// same way [`ModLuaRuntime::compile_chunk_cached`] does for mod scripts.
+pub fn shared_library_searcher(roots: Vec<PathBuf>, bytecode_cache_dir: PathBuf) -> PackageSearcher {
+    Box::new(move |lua, name| {
+        let relative_path = format!("{}.lua", name.replace('.', "/"));
+
+        for root in &roots {
+            let path = root.join(&relative_path);
+            let Ok(code) = std::fs::read_to_string(&path) else {
+                continue;
+            };
+
+            let key = {
+                let mut hasher = blake3::Hasher::new();
+                hasher.update(BYTECODE_CACHE_VERSION.as_bytes());
+                hasher.update(code.as_bytes());
+                hasher.finalize().to_hex().to_string()
+            };
+
+            let bytecode: anyhow::Result<Vec<u8>> =
+                crate::cache!(read(&bytecode_cache_dir, &key) or insert { mlua::Compiler::new().compile(&code) });
+
+            let chunk_name = format!("<SHARED>/{}", path.display());
+            let text_loader = || {
+                lua.load(code.as_str())
+                    .set_name(&chunk_name)
+                    .set_mode(mlua::ChunkMode::Text)
+                    .into_function()
+            };
+
+            // Mirror `ModLuaRuntime::run`'s fallback: a binary-mode load can
+            // fail even when the cache lookup itself succeeded (a stale
+            // format despite the version tag, a truncated cache entry), in
+            // which case we still have the source on hand to recompile.
+            let loader = match bytecode {
+                Ok(bytecode) => match lua
+                    .load(bytecode)
+                    .set_name(&chunk_name)
+                    .set_mode(mlua::ChunkMode::Binary)
+                    .into_function()
+                {
+                    Ok(loader) => loader,
+                    Err(err) => {
+                        log::warn!("Cached bytecode for {chunk_name} failed to load, falling back to text: {err}");
+                        text_loader()?
+                    }
+                },
+                Err(err) => {
+                    log::warn!("Failed to get cached bytecode for {chunk_name}, compiling from text: {err:#}");
+                    text_loader()?
+                }
+            };
+
+            return (loader, path.display().to_string()).into_lua_multi(lua);
+        }
+
+        format!("\n\tno file matching '{name}' in any shared library root").into_lua_multi(lua)
+    })
+}
+
+/// Async counterpart to the synchronous VFS trait used by
+/// [`ModLuaRuntime::with_filesystems`], for mod scripts that need to await on
+/// real I/O (a network fetch for a remote patch fragment, a slow disk) rather
+/// than blocking the whole mod-apply pass.
+pub trait AsyncLuaFS: Send + Sync {
+    fn read<'a>(&'a self, path: &'a str) -> BoxFuture<'a, LuaResult<Vec<u8>>>;
+    fn exists<'a>(&'a self, path: &'a str) -> BoxFuture<'a, LuaResult<bool>>;
+    fn list<'a>(&'a self, path: &'a str) -> BoxFuture<'a, LuaResult<Vec<String>>>;
+}
+
+struct AsyncLuaFSHandle(Arc<dyn AsyncLuaFS>);
+
+impl LuaUserData for AsyncLuaFSHandle {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_async_method("read", |_, this, path: String| async move { this.0.read(&path).await });
+        methods.add_async_method("exists", |_, this, path: String| async move { this.0.exists(&path).await });
+        methods.add_async_method("list", |_, this, path: String| async move { this.0.list(&path).await });
+    }
+}
+
+/// Bumped whenever the Luau dialect or mlua's bytecode format changes, so that
+/// a toolchain upgrade invalidates bytecode cached under the old format
+/// instead of trying (and failing, or worse, misbehaving) to load it.
+const BYTECODE_CACHE_VERSION: &str = "luau-mlua0.10";
+
 pub struct LuaContext {
     pub document_root: Option<xml::DynamicElement>,
     pub print_arena_stats: bool,
+    /// Aborts the script at the first VM interrupt past this many (Luau raises
+    /// interrupts at safepoints such as calls and loop back-edges, not on
+    /// every instruction). `None` means no instruction budget.
+    pub max_instructions: Option<u64>,
+    /// Aborts the script once this much wall-clock time has elapsed since
+    /// its chunk was resolved and started running in [`ModLuaRuntime::run`]
+    /// (time spent loading or compiling the chunk is not counted). `None`
+    /// means no deadline.
+    pub timeout: Option<Duration>,
+    /// Rejects allocations past this many bytes of Lua-owned memory. `None`
+    /// means no ceiling.
+    pub max_memory_bytes: Option<usize>,
+}
+
+/// The reason a script was aborted by [`ModLuaRuntime::run`]'s resource limits,
+/// as opposed to an ordinary Lua runtime error. Callers can recover this via
+/// [`script_limit_exceeded`] to report e.g. "mod X was stopped after N ms"
+/// instead of a generic script failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LuaLimitKind {
+    Instructions,
+    Time,
+    Memory,
+}
+
+impl std::fmt::Display for LuaLimitKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LuaLimitKind::Instructions => write!(f, "script exceeded its instruction budget"),
+            LuaLimitKind::Time => write!(f, "script exceeded its time budget"),
+            LuaLimitKind::Memory => write!(f, "script exceeded its memory budget"),
+        }
+    }
+}
+
+impl std::error::Error for LuaLimitKind {}
+
+/// Walks a [`LuaError`]'s source chain looking for a resource-limit abort
+/// produced by [`ModLuaRuntime::run`].
+pub fn script_limit_exceeded(error: &LuaError) -> Option<LuaLimitKind> {
+    match error {
+        LuaError::CallbackError { cause, .. } => script_limit_exceeded(cause),
+        LuaError::ExternalError(error) => error.downcast_ref::<LuaLimitKind>().copied(),
+        LuaError::MemoryError(_) => Some(LuaLimitKind::Memory),
+        _ => None,
+    }
+}
+
+struct RunLimitGuard<'a> {
+    lua: &'a Lua,
+    previous_memory_limit: usize,
+}
+
+impl Drop for RunLimitGuard<'_> {
+    fn drop(&mut self) {
+        self.lua.remove_interrupt();
+        // Best-effort: if this fails the VM is already in an unrecoverable
+        // state and the error from `run` takes priority anyway.
+        let _ = self.lua.set_memory_limit(self.previous_memory_limit);
+    }
+}
+
+/// Removes `lib_table`'s `vfs` entry on drop, so cleanup still happens if
+/// the future holding it is cancelled instead of awaited to completion.
+struct VfsGuard<'a> {
+    lib_table: &'a LuaTable,
+}
+
+impl Drop for VfsGuard<'_> {
+    fn drop(&mut self) {
+        self.lib_table.set_readonly(false);
+        // Best-effort: nothing to clean up if `vfs` was never installed.
+        let _ = self.lib_table.raw_remove("vfs");
+        self.lib_table.set_readonly(true);
+    }
 }
 
 macro_rules! load_builtin_lib {
@@ -91,7 +241,15 @@ macro_rules! load_builtin_lib {
 }
 
 impl ModLuaRuntime {
-    pub fn new() -> LuaResult<Self> {
+    pub fn new(
+        bytecode_cache_dir: impl Into<PathBuf>,
+        extra_searchers: impl IntoIterator<Item = PackageSearcher>,
+    ) -> LuaResult<Self> {
+        // We build on the Luau backend here rather than PUC Lua: it gives us
+        // native read-only tables (`Table::set_readonly`) enforced by the VM
+        // itself instead of the old `__index`/`__newindex`/`__metatable` dance,
+        // which could always be defeated by anything that reached into
+        // `debug`, `setmetatable` or a string metatable.
         let lua = mlua::Lua::new_with(
             mlua::StdLib::TABLE | mlua::StdLib::STRING | mlua::StdLib::MATH | mlua::StdLib::PACKAGE,
             mlua::LuaOptions::new(),
@@ -101,14 +259,15 @@ impl ModLuaRuntime {
         lua.globals().raw_remove("dofile")?;
         lua.globals().raw_remove("collectgarbage")?;
         lua.globals().raw_remove("loadfile")?;
-        // While this could potentially be useful, it bypasses
-        // protected metatables so for now it's disabled.
-        lua.globals().raw_remove("rawset")?;
-        lua.protect_table(&lua.globals().raw_get::<LuaTable>("string")?)?;
-        lua.protect_table(&lua.globals().raw_get::<LuaTable>("table")?)?;
-        lua.protect_table(&lua.globals().raw_get::<LuaTable>("math")?)?;
-        Self::setup_package(&lua)?;
-        lua.protect_table(&lua.globals().raw_get::<LuaTable>("package")?)?;
+        lua.globals()
+            .raw_get::<LuaTable>("string")?
+            .set_readonly(true);
+        lua.globals().raw_get::<LuaTable>("table")?.set_readonly(true);
+        lua.globals().raw_get::<LuaTable>("math")?.set_readonly(true);
+        Self::setup_package(&lua, extra_searchers)?;
+        lua.globals()
+            .raw_get::<LuaTable>("package")?
+            .set_readonly(true);
         // This is replaced by the script environment table later.
         lua.globals().raw_remove("_G")?;
 
@@ -129,7 +288,7 @@ impl ModLuaRuntime {
         for result in lib_table.pairs() {
             let (_, value): (LuaValue, LuaValue) = result?;
             if let Some(table) = value.as_table() {
-                lua.protect_table(table)?;
+                table.set_readonly(true);
             }
         }
 
@@ -143,13 +302,37 @@ impl ModLuaRuntime {
 
         util::extend_util_library(&lua, lib_table.get::<LuaTable>("util")?).context("Failed to load util builtins")?;
 
-        lua.protect_table(&lib_table)
-            .context("Failed to make builtin mod table read-only")?;
+        lib_table.set_readonly(true);
 
-        Ok(Self { lua, lib_table })
+        // Freeze the global environment and standard library tables for good:
+        // Luau's sandbox mode enforces this at the VM level, so a script can no
+        // longer escape the tables we've marked read-only above via `debug`,
+        // `setmetatable` or any other trick that merely shuffled metatables.
+        lua.sandbox(true).context("Failed to enable Luau sandbox mode")?;
+
+        Ok(Self {
+            lua,
+            lib_table,
+            bytecode_cache_dir: bytecode_cache_dir.into(),
+        })
     }
 
-    fn setup_package(lua: &Lua) -> LuaResult<()> {
+    /// Compiles `code` to Luau bytecode, going through the on-disk bytecode
+    /// cache keyed on the source and [`BYTECODE_CACHE_VERSION`] so repeated
+    /// applications of the same mod don't re-tokenize/recompile it every time.
+    fn compile_chunk_cached(&self, code: &str) -> anyhow::Result<Vec<u8>> {
+        let key = {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(BYTECODE_CACHE_VERSION.as_bytes());
+            hasher.update(code.as_bytes());
+            hasher.finalize().to_hex().to_string()
+        };
+
+        let cache_dir = &self.bytecode_cache_dir;
+        crate::cache!(read(cache_dir, &key) or insert { mlua::Compiler::new().compile(code) })
+    }
+
+    fn setup_package(lua: &Lua, extra_searchers: impl IntoIterator<Item = PackageSearcher>) -> LuaResult<()> {
         const REQUIRE_PATH: &str = "./?.lua;/data/?.lua;/?.lua";
         let package = lua.globals().raw_get::<LuaTable>("package")?;
 
@@ -163,6 +346,13 @@ impl ModLuaRuntime {
         searchers.clear()?;
         load_builtin_lib!(lua, "vfssearcher.lua");
 
+        // Extra searchers run after the VFS one, in registration order, same
+        // as the VFS searcher's own position relative to stock Lua's.
+        for searcher in extra_searchers {
+            let position = searchers.raw_len() + 1;
+            searchers.raw_set(position, lua.create_function(move |lua, name: String| searcher(lua, name))?)?;
+        }
+
         Ok(())
     }
 
@@ -176,21 +366,108 @@ impl ModLuaRuntime {
         scoped: impl FnOnce() -> LuaResult<R>,
     ) -> LuaResult<R> {
         self.lua.scope(|scope| {
-            let vfs = self.lua.create_protected_table()?;
+            let vfs = self.lua.create_table()?;
             for (name, fs) in iter {
                 vfs.raw_set(name, scope.create_userdata(fs)?)?;
             }
+            vfs.set_readonly(true);
+
+            // `lib_table` is itself read-only so that scripts can't redefine
+            // `mod.vfs`, so we have to briefly drop back to a writable table to
+            // install/remove it ourselves.
+            self.lib_table.set_readonly(false);
             self.lib_table.raw_set("vfs", vfs)?;
+            self.lib_table.set_readonly(true);
 
             let result = scoped();
 
+            self.lib_table.set_readonly(false);
             self.lib_table.raw_remove("vfs")?;
+            self.lib_table.set_readonly(true);
 
             result
         })
     }
 
-    pub fn run(&self, code: &str, chunk_name: &str, context: &mut LuaContext) -> LuaResult<()> {
+    /// Async counterpart to [`Self::with_filesystems`]. Async userdata methods
+    /// may be polled across `.await` points that outlive the call that
+    /// installed them, so unlike the synchronous API (which borrows `dyn
+    /// LuaFS` for the duration of a scope) filesystems here must be owned
+    /// (`Arc`) for `'static`.
+    pub async fn with_filesystems_async<R>(
+        &self,
+        iter: impl IntoIterator<Item = (impl IntoLua, Arc<dyn AsyncLuaFS>)>,
+        scoped: impl Future<Output = LuaResult<R>>,
+    ) -> LuaResult<R> {
+        let vfs = self.lua.create_table()?;
+        for (name, fs) in iter {
+            vfs.raw_set(name, self.lua.create_userdata(AsyncLuaFSHandle(fs))?)?;
+        }
+        vfs.set_readonly(true);
+
+        self.lib_table.set_readonly(false);
+        self.lib_table.raw_set("vfs", vfs)?;
+        self.lib_table.set_readonly(true);
+
+        // `scoped` may be dropped before it resolves (e.g. a caller racing us
+        // against its own deadline), which would skip any cleanup placed
+        // after a plain `.await`. Restoring `mod.vfs` from `Drop` instead
+        // means it still runs on that path, not just on normal/error return.
+        let _vfs_guard = VfsGuard {
+            lib_table: &self.lib_table,
+        };
+
+        scoped.await
+    }
+
+    /// Installs the instruction/time/memory budgets from `context` for the
+    /// lifetime of the returned guard. Shared by [`Self::run`] and
+    /// [`Self::run_async`] so the limit logic only has to change in one
+    /// place.
+    ///
+    /// Note this only bounds the Lua VM itself: the interrupt hook fires on
+    /// VM steps, so it does not (and cannot) cap time spent awaiting an
+    /// [`AsyncLuaFS`] future in [`Self::run_async`] — a script stuck on a
+    /// stalled network read is not stopped by `context.timeout`. A real fix
+    /// would need the caller to race `run_async` against its own deadline.
+    fn install_limits(&self, context: &LuaContext) -> LuaResult<RunLimitGuard<'_>> {
+        let lua = &self.lua;
+
+        let previous_memory_limit = lua.set_memory_limit(context.max_memory_bytes.unwrap_or(0))?;
+
+        let max_instructions = context.max_instructions;
+        let deadline = context.timeout.map(|timeout| Instant::now() + timeout);
+        let instructions_run = Cell::new(0u64);
+
+        lua.set_interrupt(move |_| {
+            if let Some(max_instructions) = max_instructions {
+                instructions_run.set(instructions_run.get() + 1);
+                if instructions_run.get() > max_instructions {
+                    return Err(LuaError::external(LuaLimitKind::Instructions));
+                }
+            }
+
+            if let Some(deadline) = deadline {
+                if Instant::now() >= deadline {
+                    return Err(LuaError::external(LuaLimitKind::Time));
+                }
+            }
+
+            Ok(mlua::VmState::Continue)
+        });
+
+        Ok(RunLimitGuard {
+            lua,
+            previous_memory_limit,
+        })
+    }
+
+    /// Builds the per-script global environment table for `context` and
+    /// resolves `code` to a callable function, preferring
+    /// [`Self::compile_chunk_cached`]'s bytecode and falling back to
+    /// compiling from text if that bytecode fails to load. Shared by
+    /// [`Self::run`] and [`Self::run_async`].
+    fn resolve_chunk(&self, code: &str, chunk_name: &str, context: &LuaContext) -> LuaResult<LuaFunction> {
         let lua = &self.lua;
 
         let env = lua.create_overlay_table(&lua.globals())?;
@@ -205,11 +482,49 @@ impl ModLuaRuntime {
             )?;
         }
 
-        lua.load(code)
-            .set_name(chunk_name)
-            .set_mode(mlua::ChunkMode::Text)
-            .set_environment(env)
-            .exec()?;
+        match self.compile_chunk_cached(code) {
+            Ok(bytecode) => match lua
+                .load(bytecode)
+                .set_name(chunk_name)
+                .set_mode(mlua::ChunkMode::Binary)
+                .set_environment(env.clone())
+                .into_function()
+            {
+                Ok(function) => Ok(function),
+                Err(err) => {
+                    log::warn!("Cached bytecode for {chunk_name} failed to load, falling back to text: {err}");
+                    lua.load(code)
+                        .set_name(chunk_name)
+                        .set_mode(mlua::ChunkMode::Text)
+                        .set_environment(env)
+                        .into_function()
+                }
+            },
+            Err(err) => {
+                log::warn!("Failed to get cached bytecode for {chunk_name}, compiling from text: {err:#}");
+                lua.load(code)
+                    .set_name(chunk_name)
+                    .set_mode(mlua::ChunkMode::Text)
+                    .set_environment(env)
+                    .into_function()
+            }
+        }
+    }
+
+    pub fn run(&self, code: &str, chunk_name: &str, context: &mut LuaContext) -> LuaResult<()> {
+        let lua = &self.lua;
+
+        // Resolving the chunk to a function first (without running any of
+        // it) means a bytecode-vs-text fallback can never run the script
+        // twice: whichever path wins, the function below is called exactly
+        // once and its error (an ordinary script error or a [`LuaLimitKind`]
+        // budget-exceeded error) propagates as-is.
+        let function = self.resolve_chunk(code, chunk_name, context)?;
+        // Start the instruction/time budget only once the chunk is actually
+        // ready to run, so a cold bytecode-cache miss (hashing, disk I/O,
+        // compiling) doesn't eat into the script's own execution budget.
+        let _limit_guard = self.install_limits(context)?;
+        function.call::<()>(())?;
 
         if context.print_arena_stats {
             let mut gc = lua.app_data_mut::<LuaArena>().unwrap();
@@ -229,4 +544,24 @@ impl ModLuaRuntime {
 
         Ok(())
     }
+
+    /// Async counterpart to [`Self::run`]: shares the same limit setup and
+    /// bytecode-cache-with-text-fallback chunk resolution (see
+    /// [`Self::install_limits`], [`Self::resolve_chunk`]), but calls the
+    /// resolved function with [`LuaFunction::call_async`] so the script body
+    /// (and, via [`Self::with_filesystems_async`], any `mod.vfs` reads it
+    /// triggers) is driven on the caller's executor instead of running to
+    /// completion synchronously — a slow or remote-backed resource doesn't
+    /// stall the whole mod-apply pass.
+    ///
+    /// As [`Self::install_limits`] notes, the instruction/time budget does
+    /// not bound time spent awaiting inside the script (e.g. a stalled
+    /// [`AsyncLuaFS`] read); only Lua-side execution is capped.
+    pub async fn run_async(&self, code: &str, chunk_name: &str, context: &mut LuaContext) -> LuaResult<()> {
+        let function = self.resolve_chunk(code, chunk_name, context)?;
+        let _limit_guard = self.install_limits(context)?;
+        function.call_async::<()>(()).await?;
+
+        Ok(())
+    }
 }